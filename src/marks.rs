@@ -1,3 +1,5 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::fmt;
 use super::trigram;
 
@@ -8,16 +10,109 @@ pub type Marks     = Vec<u32>;
 const NULL_RECORD_ID: RecordId  = 0;
 const FIRST_RECORD_ID: RecordId = 1;
 
+/// A single criterion in the ranking pipeline used by
+/// [`MarksRecords::sort_with_rules`]. Rules are applied in order, so ties
+/// left by one rule are broken by the next.
+pub enum RankingRule {
+    /// Records whose student id case-insensitively starts with the query
+    /// rank above records that merely contain or resemble it.
+    ExactPrefix,
+    /// Records requiring fewer character edits to turn the query into the
+    /// student id (or vice versa) rank higher.
+    TypoCount,
+    /// For multi-word ids, records whose matched tokens sit closer
+    /// together rank higher; ids with fewer than two matched tokens get
+    /// distance 0 and so rank first under this rule.
+    Proximity,
+    /// The n-gram cosine similarity computed by [`trigram::Index`].
+    TrigramScore,
+}
+
+impl RankingRule {
+    /// `ExactPrefix -> TypoCount -> TrigramScore`, the ordering
+    /// `MarksRecords::sort_with` uses by default.
+    pub const DEFAULT: [RankingRule; 3] =
+        [RankingRule::ExactPrefix, RankingRule::TypoCount, RankingRule::TrigramScore];
+
+    // Lower is better, so the rule's output can feed straight into a
+    // stable ascending sort, consistent across every rule.
+    fn key(&self, query: &str, student_id: &str, trigram_score: f32) -> i64 {
+        match self {
+            RankingRule::ExactPrefix => {
+                let student_id = student_id.to_lowercase();
+                let query = query.to_lowercase();
+                if student_id.starts_with(&query) { 0 } else { 1 }
+            }
+            RankingRule::TypoCount => levenshtein(query, student_id) as i64,
+            RankingRule::Proximity => proximity(query, student_id) as i64,
+            RankingRule::TrigramScore => (trigram_score * -1e5) as i64,
+        }
+    }
+}
+
+// Case-insensitive Levenshtein distance.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, &ca) in a.iter().enumerate() {
+        let mut prev_diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            let cost = if ca == cb { 0 } else { 1 };
+            let new_value = (prev_diag + cost).min(above + 1).min(row[j] + 1);
+            prev_diag = above;
+            row[j + 1] = new_value;
+        }
+    }
+    row[b.len()]
+}
+
+// How far apart (in token positions) the tokens of `student_id` that
+// case-insensitively contain `query` are; 0 if fewer than two tokens match.
+fn proximity(query: &str, student_id: &str) -> usize {
+    if query.is_empty() {
+        return 0;
+    }
+    let query = query.to_lowercase();
+    let matched: Vec<usize> = student_id.split_whitespace()
+        .enumerate()
+        .filter(|(_, word)| word.to_lowercase().contains(&query))
+        .map(|(i, _)| i)
+        .collect();
+    match (matched.first(), matched.last()) {
+        (Some(&first), Some(&last)) if matched.len() >= 2 => last - first,
+        _ => 0,
+    }
+}
+
 /// Container of student marks.
 #[derive(Serialize, Deserialize)]
 pub struct MarksRecords {
     next_record_id: RecordId,
-    records: Vec<(RecordId, StudentId, Marks)>
+    records: Vec<(RecordId, StudentId, Marks)>,
+    // Derived from `records`; kept out of the serialized form and rebuilt
+    // on load, since it is only a lookup accelerator, not real state.
+    #[serde(skip)]
+    index: trigram::Index
 }
 
 impl MarksRecords {
     pub fn new() -> MarksRecords {
-        MarksRecords{ next_record_id: FIRST_RECORD_ID, records: Vec::new() }
+        MarksRecords
+            { next_record_id: FIRST_RECORD_ID
+            , records: Vec::new()
+            , index: trigram::Index::new()
+            }
+    }
+    // Re-derive the index from `records`, keyed by vector position. Needed
+    // whenever positions shift wholesale, e.g. after a sort.
+    fn rebuild_index(&mut self) {
+        self.index.rebuild
+            ( self.records.iter().enumerate()
+                .map(|(i, (_, student_id, _))| (i as RecordId, student_id.as_str()))
+            );
     }
     #[allow(unused)]
     pub fn len(&self) -> usize {
@@ -29,10 +124,13 @@ impl MarksRecords {
     }
     pub fn clear(&mut self) {
         self.records.clear();
+        self.index = trigram::Index::new();
     }
     /// Add student with empty marks.
     pub fn add_student (&mut self, student_id: StudentId)
     {
+        let position = self.records.len() as RecordId;
+        self.index.index_record(position, &student_id);
         self.records.push((NULL_RECORD_ID, student_id, vec![]));
     }
     /// Update marks of the record at the top.
@@ -48,11 +146,72 @@ impl MarksRecords {
         }
         Ok (())
     }
-    /// Sort records by descending student id's similarity with argument `s`.
+    /// Sort records by descending student id's similarity with argument `s`,
+    /// using the default ranking-rule ordering (see [`RankingRule::DEFAULT`]).
     pub fn sort_with(&mut self, s: &str) {
-        self.records.sort_by_key( |(_, student_id, _)|
-            (trigram::score(student_id, s) * -1e5) as i32
-        );
+        self.sort_with_rules(s, &RankingRule::DEFAULT);
+    }
+    /// Sort records with a custom ranking-rule pipeline: records are
+    /// compared rule by rule in order, so ties in one rule are broken by
+    /// the next. See [`RankingRule`].
+    pub fn sort_with_rules(&mut self, s: &str, rules: &[RankingRule]) {
+        let scores = self.index.query(s);
+        let mut keyed: Vec<(Vec<i64>, (RecordId, StudentId, Marks))> = Vec::new();
+        for (i, record) in self.records.drain(..).enumerate() {
+            let trigram_score = scores.get(&(i as RecordId)).copied().unwrap_or(0.0);
+            let key = rules.iter()
+                .map(|rule| rule.key(s, &record.1, trigram_score))
+                .collect();
+            keyed.push((key, record));
+        }
+        keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+        self.records = keyed.into_iter().map(|(_, record)| record).collect();
+        self.rebuild_index();
+    }
+    /// The positions of the `k` records best matching `query`, descending
+    /// by trigram cosine score, selected with a bounded min-heap of size
+    /// `k` (push, then pop the worst once the heap exceeds `k`) rather
+    /// than sorting every record. Used by `top_k`.
+    fn top_k_positions(&self, query: &str, k: usize) -> Vec<(RecordId, f32)> {
+        let scores = self.index.query(query);
+        // The heap never holds more than `k` entries, but `k` is an
+        // unconstrained public parameter, so cap the capacity hint to the
+        // number of candidates rather than risk a `k + 1` that overflows
+        // or, for a huge `k`, tries to reserve space no candidate set
+        // could ever fill.
+        let mut heap: BinaryHeap<Reverse<(i64, RecordId)>> =
+            BinaryHeap::with_capacity(scores.len().min(k.saturating_add(1)));
+        for (&position, &score) in scores.iter() {
+            heap.push(Reverse(((score * 1e6) as i64, position)));
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+        let mut positions: Vec<(i64, RecordId)> =
+            heap.into_iter().map(|Reverse(entry)| entry).collect();
+        positions.sort_by(|a, b| b.cmp(a));
+        positions.into_iter()
+            .map(|(_, position)| (position, scores[&position]))
+            .collect()
+    }
+    /// The `k` records best matching `query` by trigram cosine score,
+    /// descending, without disturbing `records`' order. For large rosters,
+    /// prefer this over `sort_with` when only a preview of matches is
+    /// needed, since it avoids a full sort when `k` is much smaller than
+    /// the roster size.
+    ///
+    /// Ranks purely by cosine score: it does not apply the
+    /// ExactPrefix/TypoCount rules that `sort_with` applies first, so
+    /// its top match can disagree with `sort_with`'s. Don't use it to
+    /// preview what `sort_with` would put at `records[0]` — use
+    /// `render_top_k_with_matches` after `sort_with` for that.
+    pub fn top_k(&self, query: &str, k: usize) -> Vec<(RecordId, &StudentId, f32)> {
+        self.top_k_positions(query, k).into_iter()
+            .map(|(position, score)| {
+                let (record_id, student_id, _) = &self.records[position as usize];
+                (*record_id, student_id, score)
+            })
+            .collect()
     }
     pub fn to_json_string(&self) -> Result<String, String> {
         serde_json::to_string(self).map_err(|e|
@@ -60,9 +219,11 @@ impl MarksRecords {
         )
     }
     pub fn from_json_str(s: &str) -> Result<MarksRecords, String> {
-        serde_json::from_str(s).map_err(|e|
+        let mut marks_records: MarksRecords = serde_json::from_str(s).map_err(|e|
             format!("failed deserializing {}: {}", s, e)
-        )
+        )?;
+        marks_records.rebuild_index();
+        Ok (marks_records)
     }
     pub fn export_string(&self) -> String {
         let itemize = |marks: &Marks| marks.iter()
@@ -86,6 +247,87 @@ impl MarksRecords {
                 .concat()
             )
     }
+    /// Render like `Display`, but with a caret line under each student id
+    /// marking the bytes it shares with `query` via matched n-grams, so the
+    /// UI can show *why* a record ranked where it did.
+    pub fn render_with_matches(&self, query: &str) -> String {
+        self.records.iter()
+            .map(|record| render_record_with_matches(record, query))
+            .collect()
+    }
+    /// Like `render_with_matches`, but renders only the first `k` records
+    /// in their current order, for responsive previews of large rosters.
+    /// Call `sort_with`/`sort_with_rules` first so the preview agrees with
+    /// whichever record `set_marks_at_top` would edit: unlike `top_k`,
+    /// this deliberately does not independently re-rank by its own
+    /// criterion, which could otherwise show a different top match than
+    /// the one Enter would write marks into.
+    pub fn render_top_k_with_matches(&self, query: &str, k: usize) -> String {
+        self.records.iter()
+            .take(k)
+            .map(|record| render_record_with_matches(record, query))
+            .collect()
+    }
+}
+
+// The line (and, if any n-grams match, caret line) for one record, shared
+// by `render_with_matches` and `render_top_k_with_matches`.
+fn render_record_with_matches(record: &(RecordId, StudentId, Marks), query: &str) -> String {
+    let (record_id, student_id, marks) = record;
+    let mut out = String::new();
+    if *record_id == NULL_RECORD_ID {
+        out.push_str("    ");
+    } else {
+        out.push_str(&format!("{:<4.4}", record_id));
+    }
+    out.push_str(&format!("{:24.24}", student_id));
+    if !marks.is_empty() {
+        let sum: u32 = marks.iter().sum();
+        out.push_str(&format!(" {:>10} = {:?}", sum, marks));
+    }
+    out.push('\n');
+    let spans = trigram::matched_spans(student_id, query);
+    if !spans.is_empty() {
+        out.push_str(&caret_line(student_id, &spans));
+        out.push('\n');
+    }
+    out
+}
+
+// A line of carets under the (possibly truncated) student id column of
+// `render_with_matches`, marking the byte ranges in `spans`. The name line
+// above is truncated/padded by *char* count (the `{:24.24}` format spec),
+// not by byte count, so `spans`' byte offsets are remapped to char columns
+// before building the caret string; otherwise multi-byte student ids
+// (e.g. "Søren") would mis-align the carets with the letters above them.
+fn caret_line(student_id: &str, spans: &[(usize, usize)]) -> String {
+    let byte_to_char = byte_to_char_columns(student_id);
+    let width = student_id.chars().count().min(24);
+    let mut carets = vec![b' '; width];
+    for &(start, end) in spans {
+        let start_char = byte_to_char[start.min(student_id.len())];
+        let end_char = byte_to_char[end.min(student_id.len())];
+        for c in carets[start_char.min(width)..end_char.min(width)].iter_mut() {
+            *c = b'^';
+        }
+    }
+    format!("    {}", String::from_utf8(carets).unwrap())
+}
+
+// `result[byte_offset]` is the char column `byte_offset` falls within, for
+// every offset in `0..=s.len()` (including mid-character byte offsets,
+// which fall back to the char they're part of).
+fn byte_to_char_columns(s: &str) -> Vec<usize> {
+    let mut boundaries: Vec<usize> = s.char_indices().map(|(i, _)| i).collect();
+    boundaries.push(s.len());
+    let mut columns = vec![0usize; s.len() + 1];
+    for (char_idx, w) in boundaries.windows(2).enumerate() {
+        for c in columns[w[0]..w[1]].iter_mut() {
+            *c = char_idx;
+        }
+    }
+    columns[s.len()] = boundaries.len() - 1;
+    columns
 }
 
 impl fmt::Display for MarksRecords {
@@ -167,4 +409,82 @@ mod tests {
         let deserialized: MarksRecords = serde_json::from_str(&serialized).unwrap();
         assert_eq!(marks_records.records, deserialized.records);
     }
+    #[test]
+    fn typo_count_breaks_exact_prefix_tie() {
+        // Both ids are exact prefix matches for "smith", so the tie is
+        // broken by the next rule: fewer edits to reach "smithe" wins.
+        let mut marks_records = MarksRecords::new();
+        marks_records.add_student(String::from("smithson"));
+        marks_records.add_student(String::from("smithe"));
+        marks_records.sort_with("smith");
+        assert_eq!(marks_records.records[0].1, "smithe");
+    }
+    #[test]
+    fn proximity_breaks_exact_prefix_tie() {
+        // Neither id starts with "an", so ExactPrefix ties between them;
+        // Proximity breaks the tie by how close the two matched tokens sit.
+        let mut marks_records = MarksRecords::new();
+        marks_records.add_student(String::from("zz anna xx ann"));
+        marks_records.add_student(String::from("zz anna ann"));
+        marks_records.sort_with_rules
+            ("an", &[RankingRule::ExactPrefix, RankingRule::Proximity]);
+        assert_eq!(marks_records.records[0].1, "zz anna ann");
+    }
+    #[test]
+    fn top_k_returns_best_matches_descending() {
+        let mut marks_records = MarksRecords::new();
+        marks_records.add_student(String::from("smith"));
+        marks_records.add_student(String::from("smithson"));
+        marks_records.add_student(String::from("jones"));
+        let top = marks_records.top_k("smith", 2);
+        assert_eq!(top.len(), 2);
+        assert!(top[0].2 >= top[1].2);
+        let names: Vec<&str> = top.iter().map(|(_, id, _)| id.as_str()).collect();
+        assert!(names.contains(&"smith"));
+        assert!(names.contains(&"smithson"));
+    }
+    #[test]
+    fn top_k_does_not_overflow_with_max_k() {
+        let mut marks_records = MarksRecords::new();
+        marks_records.add_student(String::from("smith"));
+        let top = marks_records.top_k("smith", usize::MAX);
+        assert_eq!(top.len(), 1);
+    }
+    #[test]
+    fn render_top_k_with_matches_agrees_with_sort_with() {
+        // "xann" has a higher cosine score against "ann" than the exact
+        // prefix match "annzzzzzzzzzzzzzzzzzzzzz", so a preview ranked
+        // independently by cosine score (the old `top_k`-based behavior)
+        // would show "xann" first while `sort_with` puts the exact prefix
+        // match at `records[0]` — the record `set_marks_at_top` edits on
+        // Enter. The preview must agree with `records[0]`.
+        let mut marks_records = MarksRecords::new();
+        marks_records.add_student(String::from("xann"));
+        marks_records.add_student(String::from("annzzzzzzzzzzzzzzzzzzzzz"));
+        marks_records.sort_with("ann");
+        assert_eq!(marks_records.records[0].1, "annzzzzzzzzzzzzzzzzzzzzz");
+        let preview = marks_records.render_top_k_with_matches("ann", 1);
+        assert!(preview.starts_with("    annzzzzzzzzzzzzzzzzzzzzz"));
+    }
+    #[test]
+    fn render_with_matches_marks_matched_bytes() {
+        let mut marks_records = MarksRecords::new();
+        marks_records.add_student(String::from("abcd"));
+        let rendered = marks_records.render_with_matches("bc");
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], "     ^^ ");
+    }
+    #[test]
+    fn render_with_matches_aligns_carets_for_multibyte_names() {
+        let mut marks_records = MarksRecords::new();
+        marks_records.add_student(String::from("Müller"));
+        let rendered = marks_records.render_with_matches("ller");
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 2);
+        // "ller" is the 3rd through 6th *character* of "Müller" (the 2-byte
+        // 'ü' must count as one column, not two), so the carets line up
+        // under "ller", not one-plus column off as raw byte offsets would.
+        assert_eq!(lines[1], "      ^^^^");
+    }
 }
\ No newline at end of file
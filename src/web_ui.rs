@@ -27,6 +27,9 @@ const DONE_BTN  : &str = "#done_btn";
 const INPUT     : &str = "#input";
 const OUTPUT    : &str = "#output";
 
+// How many candidates `instant_query` previews per keystroke.
+const QUERY_PREVIEW_SIZE: usize = 10;
+
 // All the elements and data of the UI.
 struct WebUI {
     step_1: HtmlElement,
@@ -117,9 +120,16 @@ fn load_event_handlers(web_ui: Rc<WebUI>) {
                 return
             }
             console!(log, "sorting students with query", &student_id);
+            // Move the best match to the top so the marks-entry workflow
+            // on Enter still edits the right record...
             ui.marks_records.borrow_mut().sort_with(&student_id);
+            // ...and render only a preview of the now-sorted roster, since
+            // it can be large; render_top_k_with_matches renders the
+            // sorted order as-is instead of re-ranking, so the preview
+            // always agrees with the record Enter would edit.
             ui.output.set_value
-                ( &format!("{}", ui.marks_records.borrow()) );
+                ( &ui.marks_records.borrow()
+                    .render_top_k_with_matches(&student_id, QUERY_PREVIEW_SIZE) );
         }
     };
     let ui = web_ui.clone();